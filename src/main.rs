@@ -2,47 +2,110 @@
 
 use std::error::Error;
 use std::io::prelude::*;
-use std::{fmt, io, process};
+use std::{env, fmt, io, process};
 
 use csv;
 use edn;
 use edn::parser::Parser;
 
+/// Renders an `edn::Value`. In the default bare mode, strings, chars,
+/// and keywords are written raw and collection elements are
+/// comma-separated -- convenient for a human-readable cell, but not
+/// re-readable as EDN if the value contains a tab, newline, or quote.
+/// In literal mode, output is valid EDN: strings and chars are escaped
+/// and collection elements are space-separated.
 struct EdnPrinter<'a> {
     edn: &'a edn::Value,
+    literal: bool,
 }
 
 impl<'a> EdnPrinter<'a> {
     fn new(edn: &'a edn::Value) -> Self {
-        EdnPrinter { edn: edn }
+        EdnPrinter {
+            edn: edn,
+            literal: false,
+        }
+    }
+
+    fn literal(edn: &'a edn::Value) -> Self {
+        EdnPrinter {
+            edn: edn,
+            literal: true,
+        }
     }
-}
 
-impl<'a> From<&'a edn::Value> for EdnPrinter<'a> {
-    fn from(edn: &'a edn::Value) -> Self {
-        EdnPrinter::new(edn)
+    fn child(&self, edn: &'a edn::Value) -> Self {
+        EdnPrinter {
+            edn: edn,
+            literal: self.literal,
+        }
     }
 }
 
 impl<'a> fmt::Display for EdnPrinter<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sep = if self.literal { " " } else { "," };
+
         match self.edn {
             edn::Value::Nil => write!(f, "nil"),
             edn::Value::Boolean(b) => write!(f, "{}", &b),
-            edn::Value::String(s) => write!(f, "{}", &s),
-            edn::Value::Char(c) => write!(f, "{}", &c),
+            edn::Value::String(s) => {
+                if self.literal {
+                    write!(f, "\"")?;
+                    for c in s.chars() {
+                        match c {
+                            '"' => write!(f, "\\\"")?,
+                            '\\' => write!(f, "\\\\")?,
+                            '\n' => write!(f, "\\n")?,
+                            '\t' => write!(f, "\\t")?,
+                            '\r' => write!(f, "\\r")?,
+                            _ => write!(f, "{}", c)?,
+                        }
+                    }
+                    write!(f, "\"")
+                } else {
+                    write!(f, "{}", &s)
+                }
+            }
+            edn::Value::Char(c) => {
+                if self.literal {
+                    match c {
+                        '\n' => write!(f, "\\newline"),
+                        '\t' => write!(f, "\\tab"),
+                        '\r' => write!(f, "\\return"),
+                        ' ' => write!(f, "\\space"),
+                        _ if c.is_ascii_graphic() => write!(f, "\\{}", c),
+                        _ if (*c as u32) <= 0xffff => write!(f, "\\u{:04x}", *c as u32),
+                        // EDN's \uNNNN is a single scalar value, not a
+                        // UTF-16 code unit, so it cannot represent a
+                        // codepoint above the BMP as one escape without
+                        // losing round-trippability. Fall back to the
+                        // literal `\c` form, which is valid EDN and
+                        // reads back as the same char.
+                        _ => write!(f, "\\{}", c),
+                    }
+                } else {
+                    write!(f, "{}", &c)
+                }
+            }
             edn::Value::Symbol(s) => write!(f, "{}", &s),
-            edn::Value::Keyword(k) => write!(f, "{}", &k),
+            edn::Value::Keyword(k) => {
+                if self.literal {
+                    write!(f, ":{}", &k)
+                } else {
+                    write!(f, "{}", &k)
+                }
+            }
             edn::Value::Integer(i) => write!(f, "{}", &i),
             edn::Value::Float(flt) => write!(f, "{}", &flt),
             edn::Value::List(values) => {
                 write!(f, "(")?;
                 values
                     .iter()
-                    .map(EdnPrinter::from)
+                    .map(|v| self.child(v))
                     .try_fold(true, |is_first, value| {
                         if !is_first {
-                            write!(f, ",")?;
+                            write!(f, "{}", sep)?;
                         }
 
                         write!(f, "{}", value)?;
@@ -55,10 +118,10 @@ impl<'a> fmt::Display for EdnPrinter<'a> {
                 write!(f, "[")?;
                 values
                     .iter()
-                    .map(EdnPrinter::from)
+                    .map(|v| self.child(v))
                     .try_fold(true, |is_first, value| {
                         if !is_first {
-                            write!(f, ",")?;
+                            write!(f, "{}", sep)?;
                         }
 
                         write!(f, "{}", value)?;
@@ -69,10 +132,10 @@ impl<'a> fmt::Display for EdnPrinter<'a> {
             edn::Value::Map(m) => {
                 write!(f, "{}", "{")?;
                 m.iter()
-                    .map(|(k, v)| (EdnPrinter::from(k), EdnPrinter::from(v)))
+                    .map(|(k, v)| (self.child(k), self.child(v)))
                     .try_fold(true, |is_first, (k, v)| {
                         if !is_first {
-                            write!(f, ",")?;
+                            write!(f, "{}", sep)?;
                         }
 
                         write!(f, "{} {}", k, v)?;
@@ -84,10 +147,10 @@ impl<'a> fmt::Display for EdnPrinter<'a> {
                 write!(f, "{}", "#{")?;
                 values
                     .iter()
-                    .map(|v| EdnPrinter::from(v))
+                    .map(|v| self.child(v))
                     .try_fold(true, |is_first, v| {
                         if !is_first {
-                            write!(f, ",")?;
+                            write!(f, "{}", sep)?;
                         }
 
                         write!(f, "{}", v)?;
@@ -97,24 +160,81 @@ impl<'a> fmt::Display for EdnPrinter<'a> {
             }
             edn::Value::Tagged(tag, value) => {
                 write!(f, "#{}", tag)?;
-                write!(f, " {}", EdnPrinter::from(value.as_ref()))
+                write!(f, " {}", self.child(value.as_ref()))
             }
         }
     }
 }
 
+/// Converts a byte offset into the full input buffer to a 1-based
+/// (line, column) pair, so errors can be reported in human terms even
+/// though the parser itself only knows about byte offsets.
+fn line_col(buffer: &str, offset: usize) -> (usize, usize) {
+    let mut line = 1;
+    let mut col = 1;
+
+    for ch in buffer[..offset.min(buffer.len())].chars() {
+        if ch == '\n' {
+            line += 1;
+            col = 1;
+        } else {
+            col += 1;
+        }
+    }
+
+    (line, col)
+}
+
 #[derive(Debug)]
 struct ParseError {
-    linenum: usize,
+    line: usize,
+    col: usize,
+    line_text: String,
+    span_len: usize,
     cause: edn::parser::Error,
 }
 
+impl ParseError {
+    fn new(buffer: &str, cause: edn::parser::Error) -> Self {
+        // A span that starts at EOF right after a trailing newline has no
+        // line of its own to show; point at the end of the previous line
+        // instead.
+        let anchor = if cause.lo > 0
+            && cause.lo >= buffer.len()
+            && buffer.as_bytes().get(cause.lo - 1) == Some(&b'\n')
+        {
+            cause.lo - 1
+        } else {
+            cause.lo.min(buffer.len())
+        };
+
+        let (line, col) = line_col(buffer, anchor);
+        let line_text = buffer.lines().nth(line - 1).unwrap_or("").to_string();
+
+        let hi = cause.hi.max(cause.lo).min(buffer.len());
+        let lo = cause.lo.min(hi);
+        let char_span = buffer.get(lo..hi).map(|s| s.chars().count()).unwrap_or(1).max(1);
+        let span_len = char_span.min(line_text.chars().count().saturating_sub(col - 1).max(1));
+
+        ParseError {
+            line,
+            col,
+            line_text,
+            span_len,
+            cause,
+        }
+    }
+}
+
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}:{}: {}", self.line, self.col, self.cause.message)?;
+        writeln!(f, "{}", self.line_text)?;
         write!(
             f,
-            "{} ({}, {}): {} ",
-            self.linenum, self.cause.lo, self.cause.hi, self.cause.message
+            "{}{}",
+            " ".repeat(self.col - 1),
+            "^".repeat(self.span_len)
         )
     }
 }
@@ -125,67 +245,248 @@ impl Error for ParseError {
     }
 }
 
-fn run() -> Result<(), Box<dyn Error>> {
-    use std::collections::BTreeSet;
+/// The output dialect: the subset of `csv::WriterBuilder` knobs we expose
+/// as command line flags, plus whether to emit a header row and whether
+/// to flatten nested structure into dotted/indexed columns.
+struct Options {
+    delimiter: u8,
+    quote: u8,
+    escape: Option<u8>,
+    double_quote: bool,
+    terminator: csv::Terminator,
+    quote_style: csv::QuoteStyle,
+    headers: bool,
+    flatten: bool,
+    reverse: bool,
+    edn_literals: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            delimiter: b'\t',
+            quote: b'"',
+            escape: None,
+            double_quote: true,
+            terminator: csv::Terminator::Any(b'\n'),
+            quote_style: csv::QuoteStyle::Necessary,
+            headers: true,
+            flatten: false,
+            reverse: false,
+            edn_literals: false,
+        }
+    }
+}
+
+/// Infers an EDN scalar from a delimited-text field: an integer, a
+/// float, a boolean, `nil` for an empty field, otherwise a string.
+fn infer_value(field: &str) -> edn::Value {
+    if field.is_empty() {
+        return edn::Value::Nil;
+    }
+
+    if let Ok(i) = field.parse::<i64>() {
+        return edn::Value::Integer(i);
+    }
+
+    if let Ok(f) = field.parse::<f64>() {
+        return edn::Value::Float(f.into());
+    }
+
+    match field {
+        "true" => edn::Value::Boolean(true),
+        "false" => edn::Value::Boolean(false),
+        _ => edn::Value::String(field.to_string()),
+    }
+}
+
+/// Recursively walks a nested EDN value, synthesizing a dotted/indexed
+/// column name for every scalar leaf: `:a` under `:outer` becomes
+/// `outer.a`, and vector elements become `tag.0`, `tag.1`, etc.
+fn flatten_into(prefix: &str, value: &edn::Value, out: &mut std::collections::BTreeMap<String, edn::Value>) {
+    match value {
+        edn::Value::Map(m) => {
+            for (k, v) in m.iter() {
+                let key = match k {
+                    edn::Value::Keyword(k) => k.clone(),
+                    other => format!("{}", EdnPrinter::new(other)),
+                };
+                flatten_into(&format!("{}.{}", prefix, key), v, out);
+            }
+        }
+        edn::Value::Vector(values) => {
+            for (i, v) in values.iter().enumerate() {
+                flatten_into(&format!("{}.{}", prefix, i), v, out);
+            }
+        }
+        _ => {
+            out.insert(prefix.to_string(), value.clone());
+        }
+    }
+}
+
+fn usage() -> String {
+    "usage: edn2csv [options] < input.edn > output.csv
+
+options:
+    --delimiter <char>     field delimiter (default: tab)
+    --quote <char>         quote character (default: \")
+    --escape <char>        escape character for quotes when not doubling (default: \\ on output, none with --reverse)
+    --double-quote         escape quotes by doubling them (default)
+    --no-double-quote      escape quotes with --escape instead of doubling
+    --terminator <crlf|lf> record terminator (default: lf)
+    --quote-style <style>  always | necessary | non-numeric (default: necessary)
+    --no-headers           don't emit a header row
+    --flatten              expand nested maps/vectors into dotted/indexed columns
+    --reverse              read delimited text and emit EDN maps instead
+    --edn-literals         emit cells as valid, escaped EDN instead of bare values"
+        .to_string()
+}
+
+fn parse_byte(flag: &str, arg: &str) -> Result<u8, Box<dyn Error>> {
+    match arg {
+        "\\t" => Ok(b'\t'),
+        "\\n" => Ok(b'\n'),
+        "\\r" => Ok(b'\r'),
+        _ if arg.len() == 1 => Ok(arg.as_bytes()[0]),
+        _ => Err(format!("{}: expected a single character, got {:?}", flag, arg).into()),
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(args: I) -> Result<Options, Box<dyn Error>> {
+    let mut options = Options::default();
+    let mut args = args;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--delimiter" => {
+                let val = args.next().ok_or("--delimiter requires an argument")?;
+                options.delimiter = parse_byte("--delimiter", &val)?;
+            }
+            "--quote" => {
+                let val = args.next().ok_or("--quote requires an argument")?;
+                options.quote = parse_byte("--quote", &val)?;
+            }
+            "--escape" => {
+                let val = args.next().ok_or("--escape requires an argument")?;
+                options.escape = Some(parse_byte("--escape", &val)?);
+            }
+            "--double-quote" => options.double_quote = true,
+            "--no-double-quote" => options.double_quote = false,
+            "--terminator" => {
+                let val = args.next().ok_or("--terminator requires an argument")?;
+                options.terminator = match val.to_lowercase().as_str() {
+                    "crlf" => csv::Terminator::CRLF,
+                    "lf" => csv::Terminator::Any(b'\n'),
+                    _ => return Err(format!("--terminator: expected crlf or lf, got {:?}", val).into()),
+                };
+            }
+            "--quote-style" => {
+                let val = args.next().ok_or("--quote-style requires an argument")?;
+                options.quote_style = match val.to_lowercase().as_str() {
+                    "always" => csv::QuoteStyle::Always,
+                    "necessary" => csv::QuoteStyle::Necessary,
+                    "non-numeric" => csv::QuoteStyle::NonNumeric,
+                    _ => {
+                        return Err(format!(
+                            "--quote-style: expected always, necessary, or non-numeric, got {:?}",
+                            val
+                        )
+                        .into())
+                    }
+                };
+            }
+            "--no-headers" => options.headers = false,
+            "--flatten" => options.flatten = true,
+            "--reverse" => options.reverse = true,
+            "--edn-literals" => options.edn_literals = true,
+            "--help" => {
+                println!("{}", usage());
+                process::exit(0);
+            }
+            other => return Err(format!("unrecognized argument: {}\n\n{}", other, usage()).into()),
+        }
+    }
+
+    Ok(options)
+}
+
+fn run(options: Options) -> Result<(), Box<dyn Error>> {
+    use std::collections::{BTreeMap, BTreeSet};
 
-    let mut records = vec![];
+    let mut records: Vec<BTreeMap<String, edn::Value>> = vec![];
     let mut columns: BTreeSet<String> = BTreeSet::new();
 
-    let stdin = io::stdin();
-    for (idx, line) in stdin.lock().lines().enumerate() {
-        let line = line?;
-        let mut parser = Parser::new(&line);
-
-        if let Some(edn) = parser.read().transpose().or_else(|e| {
-            Err(ParseError {
-                linenum: idx,
-                cause: e,
-            })
-        })? {
-            match edn {
-                edn::Value::Map(m) => {
-                    let keys: Vec<String> = m
-                        .keys()
-                        .filter_map(|key| match key {
-                            edn::Value::Keyword(k) => Some(k.clone()),
-                            _ => {
-                                eprintln!("Skipping non keyword key: {}", EdnPrinter::new(&key));
-                                None
-                            }
-                        })
-                        .collect();
-
-                    columns.extend(keys);
-                    records.push(m);
+    let mut buffer = String::new();
+    io::stdin().lock().read_to_string(&mut buffer)?;
+
+    let mut parser = Parser::new(&buffer);
+    while let Some(edn) = parser
+        .read()
+        .transpose()
+        .or_else(|e| Err(ParseError::new(&buffer, e)))?
+    {
+        match edn {
+            edn::Value::Map(m) => {
+                let mut record = BTreeMap::new();
+
+                for (key, value) in m.iter() {
+                    let key = match key {
+                        edn::Value::Keyword(k) => k.clone(),
+                        _ => {
+                            eprintln!("Skipping non keyword key: {}", EdnPrinter::new(&key));
+                            continue;
+                        }
+                    };
+
+                    if options.flatten {
+                        flatten_into(&key, value, &mut record);
+                    } else {
+                        record.insert(key, value.clone());
+                    }
                 }
-                _ => eprintln!("Skipping non map on line {}", idx),
+
+                columns.extend(record.keys().cloned());
+                records.push(record);
             }
+            _ => eprintln!("Skipping non map value"),
         }
     }
 
-    let mut writer = csv::WriterBuilder::new()
-        .delimiter(b'\t')
-        .from_writer(io::stdout());
+    let mut builder = csv::WriterBuilder::new();
+    builder
+        .delimiter(options.delimiter)
+        .quote(options.quote)
+        .double_quote(options.double_quote)
+        .terminator(options.terminator)
+        .quote_style(options.quote_style);
 
-    for c in &columns {
-        writer.write_field(c)?;
+    if let Some(escape) = options.escape {
+        builder.escape(escape);
     }
-    writer.write_record(None::<&[u8]>)?;
 
-    let columns: Vec<edn::Value> = columns
-        .iter()
-        .map(|k| edn::Value::Keyword(k.to_string()))
-        .collect();
+    let mut writer = builder.from_writer(io::stdout());
+
+    if options.headers {
+        for c in &columns {
+            writer.write_field(c)?;
+        }
+        writer.write_record(None::<&[u8]>)?;
+    }
 
     for r in records {
         for c in &columns {
-            if let Some(field) = r
-                .get(c)
-                .and_then(|f| Some(format!("{}", EdnPrinter::new(f))))
-            {
-                writer.write_field(field.as_bytes())?;
-            } else {
-                writer.write_field("")?;
+            let field = r.get(c).map(|f| {
+                if options.edn_literals {
+                    format!("{}", EdnPrinter::literal(f))
+                } else {
+                    format!("{}", EdnPrinter::new(f))
+                }
+            });
+
+            match field {
+                Some(field) => writer.write_field(field.as_bytes())?,
+                None => writer.write_field("")?,
             }
         }
         writer.write_record(None::<&[u8]>)?;
@@ -193,8 +494,53 @@ fn run() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// The inverse of `run`: reads delimited text with a header row from
+/// stdin and emits one EDN map per record to stdout, using the header
+/// cells as keyword keys and inferring a scalar EDN type per field.
+fn run_reverse(options: Options) -> Result<(), Box<dyn Error>> {
+    use std::collections::BTreeMap;
+
+    let mut builder = csv::ReaderBuilder::new();
+    builder
+        .delimiter(options.delimiter)
+        .quote(options.quote)
+        .double_quote(options.double_quote)
+        .terminator(options.terminator)
+        .escape(options.escape);
+
+    let mut reader = builder.from_reader(io::stdin());
+    let headers: Vec<String> = reader.headers()?.iter().map(|h| h.to_string()).collect();
+
+    for result in reader.records() {
+        let record = result?;
+        let map: BTreeMap<edn::Value, edn::Value> = headers
+            .iter()
+            .zip(record.iter())
+            .map(|(h, f)| (edn::Value::Keyword(h.clone()), infer_value(f)))
+            .collect();
+
+        println!("{}", EdnPrinter::literal(&edn::Value::Map(map)));
+    }
+
+    Ok(())
+}
+
 fn main() {
-    match run() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1)
+        }
+    };
+
+    let result = if options.reverse {
+        run_reverse(options)
+    } else {
+        run(options)
+    };
+
+    match result {
         Ok(_) => process::exit(0),
         Err(e) => {
             eprintln!("error: {}", e);
@@ -202,3 +548,94 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infer_value_empty_is_nil() {
+        assert_eq!(infer_value(""), edn::Value::Nil);
+    }
+
+    #[test]
+    fn infer_value_parses_integers() {
+        assert_eq!(infer_value("42"), edn::Value::Integer(42));
+        assert_eq!(infer_value("-7"), edn::Value::Integer(-7));
+    }
+
+    #[test]
+    fn infer_value_parses_floats() {
+        assert_eq!(infer_value("3.5"), edn::Value::Float(3.5.into()));
+    }
+
+    #[test]
+    fn infer_value_parses_booleans() {
+        assert_eq!(infer_value("true"), edn::Value::Boolean(true));
+        assert_eq!(infer_value("false"), edn::Value::Boolean(false));
+    }
+
+    #[test]
+    fn infer_value_falls_back_to_string() {
+        assert_eq!(
+            infer_value("John Doe"),
+            edn::Value::String("John Doe".to_string())
+        );
+    }
+
+    #[test]
+    fn infer_value_prefers_integer_over_float() {
+        // An integer-looking field should stay an integer rather than
+        // round-tripping through f64.
+        assert_eq!(infer_value("10"), edn::Value::Integer(10));
+    }
+
+    #[test]
+    fn flatten_into_nests_map_keys_with_dots() {
+        let mut inner = std::collections::BTreeMap::new();
+        inner.insert(
+            edn::Value::Keyword("a".to_string()),
+            edn::Value::Integer(1),
+        );
+        let value = edn::Value::Map(inner);
+
+        let mut out = std::collections::BTreeMap::new();
+        flatten_into("outer", &value, &mut out);
+
+        assert_eq!(out.get("outer.a"), Some(&edn::Value::Integer(1)));
+    }
+
+    #[test]
+    fn flatten_into_indexes_vector_elements() {
+        let value = edn::Value::Vector(vec![
+            edn::Value::Integer(1),
+            edn::Value::Integer(2),
+        ]);
+
+        let mut out = std::collections::BTreeMap::new();
+        flatten_into("tag", &value, &mut out);
+
+        assert_eq!(out.get("tag.0"), Some(&edn::Value::Integer(1)));
+        assert_eq!(out.get("tag.1"), Some(&edn::Value::Integer(2)));
+    }
+
+    #[test]
+    fn flatten_into_leaves_scalars_at_the_prefix() {
+        let mut out = std::collections::BTreeMap::new();
+        flatten_into("name", &edn::Value::String("hi".to_string()), &mut out);
+
+        assert_eq!(out.get("name"), Some(&edn::Value::String("hi".to_string())));
+    }
+
+    #[test]
+    fn reverse_mode_emits_keyword_keys_that_round_trip() {
+        // `run_reverse` builds its map keys as `edn::Value::Keyword` and
+        // prints the whole map with `EdnPrinter::literal`; the printed
+        // key must carry the leading `:` or it re-parses as a bare
+        // `Symbol`, which the forward path skips as a non keyword key.
+        let key = edn::Value::Keyword("name".to_string());
+        let rendered = format!("{}", EdnPrinter::literal(&key));
+
+        assert_eq!(rendered, ":name");
+    }
+}